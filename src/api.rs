@@ -1,25 +1,104 @@
-use crate::{ApiError, PatreonError, PatreonResult};
+use crate::client_options::{header_names, request_default_headers};
+use crate::error::from_json_slice;
+use crate::serde_helpers::{de_flexible_datetime, de_flexible_datetime_opt, de_flexible_i64};
+use crate::uri::{endpoint, parse_base_uri};
+use crate::{ApiError, ClientOptions, PatreonError, PatreonResult, TokensResponse};
 use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, AUTHORIZATION, USER_AGENT};
 use serde_derive::{Deserialize, Serialize};
+use std::fmt::{Debug, Formatter};
 use std::sync::Arc;
+use tokio::sync::Semaphore;
 use url::Url;
 
 static BASE_URI: &str = "https://www.patreon.com";
 
-#[derive(Debug, Default)]
 pub struct PatreonApi {
     pub access_token: String,
     pub agent: Arc<reqwest::Client>,
+    pub client_options: ClientOptions,
+    pub base_uri: Url,
+    // shared by every request made through this client when set
+    pub limiter: Option<Arc<Semaphore>>,
+    pub default_headers: HeaderMap,
+}
+
+impl Default for PatreonApi {
+    fn default() -> Self {
+        Self {
+            access_token: String::default(),
+            agent: Arc::default(),
+            client_options: ClientOptions::default(),
+            base_uri: Url::parse(BASE_URI).unwrap(),
+            limiter: None,
+            default_headers: HeaderMap::default(),
+        }
+    }
+}
+
+impl Debug for PatreonApi {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PatreonApi")
+            .field("access_token", &"***")
+            .field("agent", &self.agent)
+            .field("client_options", &self.client_options)
+            .field("base_uri", &self.base_uri)
+            .field("limiter", &self.limiter)
+            .field("default_headers", &header_names(&self.default_headers))
+            .finish()
+    }
 }
 
 impl PatreonApi {
+    pub fn from_tokens(tokens: &TokensResponse) -> Self {
+        Self {
+            access_token: tokens.access_token.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_base_uri(mut self, base_uri: &str) -> PatreonResult<Self> {
+        self.base_uri = parse_base_uri(base_uri)?;
+        Ok(self)
+    }
+
+    pub fn with_max_concurrency(mut self, max_concurrency: usize) -> PatreonResult<Self> {
+        // 0 permits would block every request forever
+        if max_concurrency == 0 || max_concurrency > Semaphore::MAX_PERMITS {
+            return Err(PatreonError::Message(format!(
+                "max concurrency must be between 1 and {}, got {max_concurrency}",
+                Semaphore::MAX_PERMITS
+            )));
+        }
+        self.limiter = Some(Arc::new(Semaphore::new(max_concurrency)));
+        Ok(self)
+    }
+
+    pub fn with_default_headers(mut self, default_headers: HeaderMap) -> Self {
+        self.default_headers = default_headers;
+        self
+    }
+
+    pub fn with_client_options(mut self, client_options: ClientOptions) -> PatreonResult<Self> {
+        self.agent = Arc::new(client_options.build()?);
+        self.client_options = client_options;
+        Ok(self)
+    }
+
     pub async fn current_user(&self) -> PatreonResult<User> {
-        let mut url = Url::parse(BASE_URI).unwrap();
-        url.set_path("/api/oauth2/api/current_user");
+        let url = endpoint(&self.base_uri, "/api/oauth2/api/current_user");
         let request = self.agent.get(url);
         self.call_data(request).await
     }
 
+    pub async fn current_user_pledges(&self) -> PatreonResult<Vec<Pledge>> {
+        let mut url = endpoint(&self.base_uri, "/api/oauth2/api/current_user");
+        url.query_pairs_mut().append_pair("include", "pledges");
+        let (_, pledges): (User, Vec<Pledge>) =
+            self.call_data_and_include(self.agent.get(url)).await?;
+        Ok(pledges)
+    }
+
     pub async fn identity(&self) -> PatreonResult<User> {
         self.call_data(self.identity_request(None)).await
     }
@@ -29,20 +108,62 @@ impl PatreonApi {
             .await
     }
 
+    pub async fn memberships(&self) -> PatreonResult<Vec<Member>> {
+        let (_, memberships) = self.identity_include_memberships().await?;
+        Ok(memberships)
+    }
+
+    pub async fn active_memberships(&self) -> PatreonResult<Vec<Member>> {
+        Ok(self
+            .memberships()
+            .await?
+            .into_iter()
+            .filter(|member| {
+                member
+                    .attributes
+                    .patron_status
+                    .is_some_and(|status| status.is_active())
+            })
+            .collect())
+    }
+
     pub async fn identity_include_campaign(&self) -> PatreonResult<(User, Vec<Campaign>)> {
         self.call_data_and_include(self.identity_request(IdentityIncldue::Campaign))
             .await
     }
 
+    pub async fn primary_campaign(&self) -> PatreonResult<Campaign> {
+        let (_, mut campaigns) = self.identity_include_campaign().await?;
+        match campaigns.len() {
+            0 => Err(PatreonError::NoCampaign),
+            1 => Ok(campaigns.remove(0)),
+            count => Err(PatreonError::AmbiguousCampaign(count)),
+        }
+    }
+
+    pub async fn campaigns_published(&self, allow_nsfw: bool) -> PatreonResult<Vec<Campaign>> {
+        let (_, campaigns) = self.identity_include_campaign().await?;
+        Ok(campaigns
+            .into_iter()
+            .filter(|campaign| campaign.attributes.is_displayable(allow_nsfw))
+            .collect())
+    }
+
+    pub async fn find_campaign_by_vanity(&self, vanity: &str) -> PatreonResult<Option<Campaign>> {
+        let (_, campaigns) = self.identity_include_campaign().await?;
+        Ok(campaigns
+            .into_iter()
+            .find(|campaign| campaign.attributes.vanity.eq_ignore_ascii_case(vanity)))
+    }
+
     fn identity_request(
         &self,
         include: impl Into<Option<IdentityIncldue>>,
     ) -> reqwest::RequestBuilder {
-        let mut url = Url::parse(BASE_URI).unwrap();
-        url.set_path("api/oauth2/v2/identity");
+        let mut url = endpoint(&self.base_uri, "api/oauth2/v2/identity");
         url.query_pairs_mut().append_pair(
             "fields[user]",
-            "first_name,last_name,full_name,vanity,email,about,image_url,thumb_url,created,url",
+            "first_name,last_name,full_name,vanity,email,about,image_url,thumb_url,created,url,social_connections",
         );
         let include = include.into();
         if let Some(include) = include {
@@ -64,9 +185,30 @@ impl PatreonApi {
         self.agent.get(url)
     }
 
+    pub async fn campaign_members_count(&self, campaign_id: &str) -> PatreonResult<i64> {
+        let mut url = endpoint(
+            &self.base_uri,
+            &format!("api/oauth2/v2/campaigns/{campaign_id}/members"),
+        );
+        url.query_pairs_mut().append_pair("page[count]", "1");
+        let json = self.api_call(self.agent.get(url)).await?;
+        let meta = from_json_slice::<MetaResponse>(json.as_bytes())?.meta;
+        if let Some(total) = meta.pagination.and_then(|pagination| pagination.total) {
+            return Ok(total);
+        }
+        // meta.pagination.total is not guaranteed, fall back to the campaign's patron_count
+        let mut url = endpoint(
+            &self.base_uri,
+            &format!("api/oauth2/v2/campaigns/{campaign_id}"),
+        );
+        url.query_pairs_mut()
+            .append_pair("fields[campaign]", "patron_count");
+        let campaign: ApiDocument<PatronCountAttributes> =
+            self.call_data(self.agent.get(url)).await?;
+        Ok(campaign.attributes.patron_count)
+    }
+
     pub async fn webhooks(&self) -> PatreonResult<String> {
-        let mut url = Url::parse(BASE_URI).unwrap();
-        url.set_path("api/oauth2/v2/webhooks");
         self.api_call(self.webhooks_request(None)).await
     }
 
@@ -74,8 +216,7 @@ impl PatreonApi {
         &self,
         include: impl Into<Option<WebhookIncldue>>,
     ) -> reqwest::RequestBuilder {
-        let mut url = Url::parse(BASE_URI).unwrap();
-        url.set_path("api/oauth2/v2/webhooks");
+        let mut url = endpoint(&self.base_uri, "api/oauth2/v2/webhooks");
         let include = include.into();
         if let Some(include) = include {
             url.query_pairs_mut()
@@ -86,20 +227,36 @@ impl PatreonApi {
 
     async fn api_call(&self, request: reqwest::RequestBuilder) -> PatreonResult<String> {
         let request = request
-            .header("Authorization", format!("Bearer {}", self.access_token))
-            .header("User-Agent", "Patreon-rust")
+            .header(AUTHORIZATION, format!("Bearer {}", self.access_token))
+            .header(USER_AGENT, "Patreon-rust")
+            .headers(request_default_headers(&self.default_headers))
             .build()?;
+        let _permit = match &self.limiter {
+            Some(limiter) => Some(
+                limiter
+                    .acquire()
+                    .await
+                    .map_err(|_| PatreonError::Message("request limiter closed".to_string()))?,
+            ),
+            None => None,
+        };
         tracing::debug!("REQUEST : {} : {}", request.method(), request.url());
         let response = self.agent.execute(request).await?;
         let status = response.status();
         let text = response.text().await?;
         tracing::debug!("RESPONSE : {status} : {text}");
         if status.is_success() {
-            Ok(text)
+            // JSON:API allows top-level errors next to a 2xx status
+            let errors = top_level_errors(text.as_str());
+            if errors.is_empty() {
+                Ok(text)
+            } else {
+                Err(PatreonError::PatreonApi(status, errors))
+            }
         } else {
             Err(PatreonError::PatreonApi(
                 status,
-                serde_json::from_str::<ApiErrorResponse>(text.as_str())?.errors,
+                from_json_slice::<ApiErrorResponse>(text.as_bytes())?.errors,
             ))
         }
     }
@@ -120,7 +277,7 @@ impl PatreonApi {
         request: reqwest::RequestBuilder,
     ) -> PatreonResult<(D, Vec<I>)> {
         let json = self.api_call(request).await?;
-        let response = serde_json::from_str::<DocResponseInclude<D, I>>(json.as_str())?;
+        let response = from_json_slice::<DocResponseInclude<D, I>>(json.as_bytes())?;
         Ok((response.data, response.included))
     }
 }
@@ -135,7 +292,7 @@ where
     T: for<'de> serde::Deserialize<'de>,
 {
     pub(crate) fn parse(response: impl AsRef<[u8]>) -> PatreonResult<T> {
-        Ok(serde_json::from_slice::<DocResponse<T>>(response.as_ref())?.data)
+        Ok(from_json_slice::<DocResponse<T>>(response.as_ref())?.data)
     }
 }
 
@@ -147,12 +304,44 @@ pub(crate) struct DocResponseInclude<D, I> {
     included: Vec<I>,
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct MetaResponse {
+    #[serde(default)]
+    meta: PaginationMeta,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PaginationMeta {
+    #[serde(default)]
+    pub pagination: Option<Pagination>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pagination {
+    #[serde(default)]
+    pub total: Option<i64>,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ApiDocument<A> {
     #[serde(rename = "type")]
     pub document_type: String,
     pub id: String,
     pub attributes: A,
+    #[serde(default)]
+    pub links: Option<DocumentLinks>,
+}
+
+impl<A> ApiDocument<A> {
+    pub fn self_link(&self) -> Option<&str> {
+        self.links.as_ref()?.self_link.as_deref()
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DocumentLinks {
+    #[serde(rename = "self")]
+    pub self_link: Option<String>,
 }
 
 pub type User = ApiDocument<UserAttributes>;
@@ -173,30 +362,112 @@ pub struct UserAttributes {
     pub youtube: Option<String>,
     pub twitter: Option<String>,
     pub facebook: Option<String>,
+    #[serde(deserialize_with = "de_flexible_datetime")]
     pub created: DateTime<Utc>,
     pub url: String,
+    #[serde(default)]
+    pub social_connections: Option<serde_json::Value>,
+    #[cfg(feature = "capture-unknown")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl UserAttributes {
+    pub fn discord_user_id(&self) -> Option<String> {
+        let user_id = self
+            .social_connections
+            .as_ref()?
+            .get("discord")?
+            .get("user_id")?;
+        match user_id {
+            serde_json::Value::String(user_id) => Some(user_id.clone()),
+            serde_json::Value::Number(user_id) => Some(user_id.to_string()),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct MemberAttributes {
+    #[serde(deserialize_with = "de_flexible_i64")]
     pub campaign_lifetime_support_cents: i64,
+    #[serde(deserialize_with = "de_flexible_i64")]
     pub currently_entitled_amount_cents: i64,
     pub email: Option<String>,
     pub full_name: String,
     pub is_follower: bool,
+    #[serde(default, deserialize_with = "de_flexible_datetime_opt")]
     pub last_charge_date: Option<DateTime<Utc>>,
     pub last_charge_status: Option<LastChrgeStatus>,
+    #[serde(deserialize_with = "de_flexible_i64")]
     pub lifetime_support_cents: i64,
+    #[serde(default, deserialize_with = "de_flexible_datetime_opt")]
     pub next_charge_date: Option<DateTime<Utc>>,
     pub note: String,
     pub patron_status: Option<PatronStatus>,
     pub pledge_cadence: Option<i64>,
+    #[serde(deserialize_with = "de_flexible_datetime")]
     pub pledge_relationship_start: DateTime<Utc>,
+    #[serde(deserialize_with = "de_flexible_i64")]
     pub will_pay_amount_cents: i64,
+    #[cfg(feature = "capture-unknown")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub old: String,
+    pub new: String,
+}
+
+impl MemberAttributes {
+    pub fn diff(&self, other: &MemberAttributes) -> Vec<FieldChange> {
+        fn opt<T: std::fmt::Display>(value: &Option<T>) -> String {
+            value.as_ref().map(T::to_string).unwrap_or_default()
+        }
+        let mut changes = Vec::new();
+        macro_rules! diff {
+            ($to_string:expr, $($field:ident),* $(,)?) => {
+                $(
+                    if self.$field != other.$field {
+                        changes.push(FieldChange {
+                            field: stringify!($field),
+                            old: $to_string(&self.$field),
+                            new: $to_string(&other.$field),
+                        });
+                    }
+                )*
+            };
+        }
+        diff!(
+            ToString::to_string,
+            campaign_lifetime_support_cents,
+            currently_entitled_amount_cents,
+            full_name,
+            is_follower,
+            lifetime_support_cents,
+            note,
+            pledge_relationship_start,
+            will_pay_amount_cents,
+        );
+        diff!(
+            opt,
+            email,
+            last_charge_date,
+            last_charge_status,
+            next_charge_date,
+            patron_status,
+            pledge_cadence,
+        );
+        changes
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CampaignAttributes {
+    #[serde(deserialize_with = "de_flexible_datetime")]
     pub created_at: DateTime<Utc>,
     pub creation_name: String,
     pub discord_server_id: Option<String>,
@@ -211,9 +482,11 @@ pub struct CampaignAttributes {
     pub main_video_embed: Option<String>,
     pub main_video_url: Option<String>,
     pub one_liner: Option<String>,
+    #[serde(deserialize_with = "de_flexible_i64")]
     pub patron_count: i64,
     pub pay_per_name: String,
     pub pledge_url: String,
+    #[serde(default, deserialize_with = "de_flexible_datetime_opt")]
     pub published_at: Option<DateTime<Utc>>,
     pub rss_artwork_url: Option<String>,
     pub rss_feed_title: Option<String>,
@@ -224,6 +497,38 @@ pub struct CampaignAttributes {
     pub thanks_video_url: Option<String>,
     pub url: String,
     pub vanity: String,
+    #[cfg(feature = "capture-unknown")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl CampaignAttributes {
+    pub fn is_published(&self) -> bool {
+        self.published_at.is_some()
+    }
+
+    pub fn is_displayable(&self, allow_nsfw: bool) -> bool {
+        self.is_published() && (allow_nsfw || !self.is_nsfw)
+    }
+
+    // patrons still have to append their own `auth` query parameter to read the feed
+    pub fn rss_url(&self) -> Option<String> {
+        if !self.has_rss || self.vanity.is_empty() {
+            return None;
+        }
+        let mut url = Url::parse(BASE_URI).unwrap();
+        url.path_segments_mut()
+            .unwrap()
+            .pop_if_empty()
+            .extend(&["rss", self.vanity.as_str()]);
+        Some(url.to_string())
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct PatronCountAttributes {
+    #[serde(deserialize_with = "de_flexible_i64")]
+    patron_count: i64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -231,8 +536,28 @@ struct ApiErrorResponse {
     pub errors: Vec<ApiError>,
 }
 
+// an error object that does not fit ApiError is still an error, keep its raw json as detail
+fn top_level_errors(text: &str) -> Vec<ApiError> {
+    let errors = match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(serde_json::Value::Object(mut document)) => document.remove("errors"),
+        _ => None,
+    };
+    match errors {
+        Some(serde_json::Value::Array(errors)) => errors
+            .into_iter()
+            .map(|error| {
+                serde_json::from_value(error.clone()).unwrap_or_else(|_| ApiError {
+                    detail: error.to_string(),
+                    ..Default::default()
+                })
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
 macro_rules! enum_str {
-    ($name:ident { $($variant:ident($str:expr), )* }) => {
+    (@impl $name:ident { $($variant:ident($str:expr), )* } $fallback:expr) => {
         #[derive(Clone, Copy, Debug, Eq, PartialEq)]
         pub enum $name {
             $($variant,)*
@@ -283,7 +608,8 @@ macro_rules! enum_str {
                     {
                         match value {
                             $( $str => Ok($name::$variant), )*
-                            _ => Err(E::invalid_value(::serde::de::Unexpected::Other(
+                            // 未知的值回退到指定的变体, 没有指定则报错。
+                            _ => $fallback.ok_or_else(|| E::invalid_value(::serde::de::Unexpected::Other(
                                 &format!("unknown {} variant: {}", stringify!($name), value)
                             ), &self)),
                         }
@@ -295,6 +621,12 @@ macro_rules! enum_str {
             }
         }
     };
+    ($name:ident { $($variant:ident($str:expr), )* }) => {
+        enum_str!(@impl $name { $($variant($str),)* } None);
+    };
+    ($name:ident { $($variant:ident($str:expr), )* } else $fallback:ident) => {
+        enum_str!(@impl $name { $($variant($str),)* } Some($name::$fallback));
+    };
     ($name:ident { $($variant:ident,)* } ) => {
         enum_str!(
             $name {
@@ -302,6 +634,13 @@ macro_rules! enum_str {
             }
         );
     };
+    ($name:ident { $($variant:ident,)* } else $fallback:ident) => {
+        enum_str!(
+            $name {
+             $($variant(stringify!($variant)),)*
+            } else $fallback
+        );
+    };
 }
 
 enum_str!(IdentityIncldue {
@@ -317,27 +656,814 @@ enum_str!(LastChrgeStatus {
     Refunded,
     Fraud,
     Other,
-});
+} else Other);
 
 enum_str!(PatronStatus {
     ActivePatron("active_patron"),
     DeclinedPatron("declined_patron"),
     FormerPatron("former_patron"),
-});
+    Unknown("unknown"),
+} else Unknown);
+
+impl PatronStatus {
+    pub fn is_active(&self) -> bool {
+        *self == PatronStatus::ActivePatron
+    }
+
+    pub fn is_former(&self) -> bool {
+        *self == PatronStatus::FormerPatron
+    }
+
+    // declined is a failed payment that Patreon keeps retrying (a lapse),
+    // former means the pledge was cancelled, so only active and declined still count as supporting
+    pub fn is_supporting(&self) -> bool {
+        matches!(
+            self,
+            PatronStatus::ActivePatron | PatronStatus::DeclinedPatron
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PledgeChange {
+    New,
+    Upgraded,
+    Downgraded,
+    Unchanged,
+    Churned,
+    Reactivated,
+}
+
+// supporting (PatronStatus::is_supporting, declined included) -> not supporting is a churn,
+// never-patron -> supporting is new, former -> supporting is a reactivation
+pub fn classify_pledge_change(prev: &MemberAttributes, curr: &MemberAttributes) -> PledgeChange {
+    let was_supporting = prev
+        .patron_status
+        .is_some_and(|status| status.is_supporting());
+    let is_supporting = curr
+        .patron_status
+        .is_some_and(|status| status.is_supporting());
+    match (was_supporting, is_supporting) {
+        (true, false) => PledgeChange::Churned,
+        (false, true) if prev.patron_status.is_none() => PledgeChange::New,
+        (false, true) => PledgeChange::Reactivated,
+        (false, false) => PledgeChange::Unchanged,
+        (true, true) => match curr
+            .currently_entitled_amount_cents
+            .cmp(&prev.currently_entitled_amount_cents)
+        {
+            std::cmp::Ordering::Greater => PledgeChange::Upgraded,
+            std::cmp::Ordering::Less => PledgeChange::Downgraded,
+            std::cmp::Ordering::Equal => PledgeChange::Unchanged,
+        },
+    }
+}
 
 pub type Pledge = ApiDocument<PledgeAttributes>;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PledgeAttributes {
+    #[serde(deserialize_with = "de_flexible_i64")]
     pub amount_cents: i64,
+    #[serde(deserialize_with = "de_flexible_datetime")]
     pub created_at: DateTime<Utc>,
+    // not present on v1 pledges
+    #[serde(default)]
     pub currency: String,
+    #[serde(default, deserialize_with = "de_flexible_datetime_opt")]
     pub declined_since: Option<DateTime<Utc>>,
     pub patron_pays_fees: bool,
+    #[serde(deserialize_with = "de_flexible_i64")]
     pub pledge_cap_cents: i64,
+    #[cfg(feature = "capture-unknown")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+pub type Post = ApiDocument<PostAttributes>;
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PostAttributes {
+    pub title: Option<String>,
+    pub content: Option<String>,
+    pub is_public: bool,
+    pub is_paid: bool,
+    #[serde(default, deserialize_with = "de_flexible_datetime_opt")]
+    pub published_at: Option<DateTime<Utc>>,
+    pub url: String,
+    pub embed_data: Option<serde_json::Value>,
+    pub embed_url: Option<String>,
+    pub app_id: Option<i64>,
+    pub app_status: Option<String>,
+    #[cfg(feature = "capture-unknown")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 enum_str!(WebhookIncldue {
     Client("client"),
     Campaign("campaign"),
 });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockResponse, MockServer};
+    use std::time::Duration;
+
+    fn api_for(server: &MockServer) -> PatreonApi {
+        PatreonApi::default()
+            .with_base_uri(server.uri.as_str())
+            .unwrap()
+    }
+
+    const MEMBER: &str = r#"{
+        "type": "member",
+        "id": "m-1",
+        "attributes": {
+            "campaign_lifetime_support_cents": 1500,
+            "currently_entitled_amount_cents": 500,
+            "email": "patron@example.com",
+            "full_name": "Patron",
+            "is_follower": false,
+            "last_charge_date": "2023-04-01T00:00:00.000+00:00",
+            "last_charge_status": "Paid",
+            "lifetime_support_cents": 1500,
+            "next_charge_date": "2023-05-01",
+            "note": "",
+            "patron_status": "active_patron",
+            "pledge_cadence": 1,
+            "pledge_relationship_start": "2023-01-01T00:00:00.000+00:00",
+            "will_pay_amount_cents": 500,
+            "brand_new_field": {"nested": true}
+        }
+    }"#;
+
+    const USER: &str = r#"{
+        "type": "user",
+        "id": "101",
+        "attributes": {
+            "first_name": "Ada",
+            "last_name": "Lovelace",
+            "full_name": "Ada Lovelace",
+            "vanity": null,
+            "email": "ada@example.com",
+            "about": null,
+            "facebook_id": null,
+            "image_url": "https://c8.patreon.com/2/200/101",
+            "thumb_url": "https://c8.patreon.com/2/100/101",
+            "youtube": null,
+            "twitter": null,
+            "facebook": null,
+            "created": "2017-10-20T21:36:23+00:00",
+            "url": "https://www.patreon.com/user?u=101"
+        },
+        "links": {"self": "https://www.patreon.com/api/user/101"}
+    }"#;
+
+    // shaped like a v1 /api/oauth2/api/current_user?include=pledges response
+    const CURRENT_USER_PLEDGES: &str = r#"{
+        "data": USER,
+        "included": [
+            {
+                "type": "pledge",
+                "id": "2001",
+                "attributes": {
+                    "amount_cents": 500,
+                    "created_at": "2018-01-02T03:04:05.000+00:00",
+                    "declined_since": null,
+                    "patron_pays_fees": false,
+                    "pledge_cap_cents": "500"
+                }
+            },
+            {
+                "type": "pledge",
+                "id": "2002",
+                "attributes": {
+                    "amount_cents": "1000",
+                    "created_at": "2019-06-07T08:09:10+00:00",
+                    "declined_since": "2019-08-01T00:00:00+00:00",
+                    "patron_pays_fees": true,
+                    "pledge_cap_cents": 1000
+                }
+            }
+        ]
+    }"#;
+
+    #[tokio::test]
+    async fn current_user_pledges_fixture() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            CURRENT_USER_PLEDGES.replace("USER", USER).as_str(),
+        )])
+        .await;
+        let api = api_for(&server);
+        let pledges = api.current_user_pledges().await.unwrap();
+        assert!(server.requests()[0]
+            .starts_with("GET /api/oauth2/api/current_user?include=pledges HTTP/1.1"));
+        assert_eq!(pledges.len(), 2);
+        assert_eq!(pledges[0].id, "2001");
+        assert_eq!(pledges[0].attributes.amount_cents, 500);
+        assert_eq!(pledges[0].attributes.pledge_cap_cents, 500);
+        assert_eq!(pledges[0].attributes.currency, "");
+        assert_eq!(pledges[0].attributes.declined_since, None);
+        assert_eq!(pledges[1].id, "2002");
+        assert_eq!(pledges[1].attributes.amount_cents, 1000);
+        assert!(pledges[1].attributes.patron_pays_fees);
+        assert!(pledges[1].attributes.declined_since.is_some());
+    }
+
+    fn campaign_json(id: &str) -> String {
+        format!(
+            r#"{{
+                "type": "campaign",
+                "id": "{id}",
+                "attributes": {{
+                    "created_at": "2018-01-01T00:00:00.000+00:00",
+                    "creation_name": "things",
+                    "discord_server_id": null,
+                    "google_analytics_id": null,
+                    "has_rss": false,
+                    "has_sent_rss_notify": false,
+                    "image_small_url": "https://c10.patreon.com/small",
+                    "image_url": null,
+                    "is_charged_immediately": true,
+                    "is_monthly": true,
+                    "is_nsfw": false,
+                    "main_video_embed": null,
+                    "main_video_url": null,
+                    "one_liner": null,
+                    "patron_count": 3,
+                    "pay_per_name": "month",
+                    "pledge_url": "/join/creator-{id}",
+                    "published_at": "2018-01-02T00:00:00.000+00:00",
+                    "rss_artwork_url": null,
+                    "rss_feed_title": null,
+                    "show_earnings": false,
+                    "summary": null,
+                    "thanks_embed": null,
+                    "thanks_msg": null,
+                    "thanks_video_url": null,
+                    "url": "https://www.patreon.com/creator-{id}",
+                    "vanity": "creator-{id}"
+                }}
+            }}"#
+        )
+    }
+
+    fn identity_with_campaigns(campaigns: &[String]) -> MockResponse {
+        let body = format!(r#"{{"data":{USER},"included":[{}]}}"#, campaigns.join(","));
+        MockResponse::json(200, body.as_str())
+    }
+
+    async fn primary_campaign_of(campaigns: &[String]) -> PatreonResult<Campaign> {
+        let server = MockServer::start(vec![identity_with_campaigns(campaigns)]).await;
+        let api = api_for(&server);
+        let campaign = api.primary_campaign().await;
+        assert!(server.requests()[0].starts_with("GET /api/oauth2/v2/identity?"));
+        campaign
+    }
+
+    #[tokio::test]
+    async fn primary_campaign_requires_exactly_one() {
+        assert!(matches!(
+            primary_campaign_of(&[]).await,
+            Err(PatreonError::NoCampaign)
+        ));
+        let primary = primary_campaign_of(&[campaign_json("1")]).await.unwrap();
+        assert_eq!(primary.id, "1");
+        assert_eq!(primary.attributes.vanity, "creator-1");
+        assert!(matches!(
+            primary_campaign_of(&[campaign_json("1"), campaign_json("2")]).await,
+            Err(PatreonError::AmbiguousCampaign(2))
+        ));
+    }
+
+    fn user_with_social_connections(social_connections: &str) -> User {
+        let user = USER.replace(
+            r#""url": "https://www.patreon.com/user?u=101""#,
+            &format!(
+                r#""url": "https://www.patreon.com/user?u=101", "social_connections": {social_connections}"#
+            ),
+        );
+        serde_json::from_str(user.as_str()).unwrap()
+    }
+
+    #[test]
+    fn discord_user_id_from_social_connections() {
+        let user = user_with_social_connections(
+            r#"{"discord": {"url": null, "user_id": "83200000000000001"}, "twitter": null}"#,
+        );
+        assert_eq!(
+            user.attributes.discord_user_id().as_deref(),
+            Some("83200000000000001")
+        );
+        let user = user_with_social_connections(r#"{"discord": {"user_id": 42}}"#);
+        assert_eq!(user.attributes.discord_user_id().as_deref(), Some("42"));
+        for social_connections in [
+            r#"{"discord": null, "twitter": {"url": null, "user_id": "7"}}"#,
+            r#"{"discord": {"url": null}}"#,
+            "null",
+        ] {
+            let user = user_with_social_connections(social_connections);
+            assert_eq!(
+                user.attributes.discord_user_id(),
+                None,
+                "{social_connections}"
+            );
+        }
+        let user: User = serde_json::from_str(USER).unwrap();
+        assert_eq!(user.attributes.discord_user_id(), None);
+    }
+
+    #[test]
+    fn member_diff_reports_only_changed_fields() {
+        let before: Member = serde_json::from_str(MEMBER).unwrap();
+        let after = MemberAttributes {
+            currently_entitled_amount_cents: 1000,
+            patron_status: Some(PatronStatus::DeclinedPatron),
+            ..before.attributes.clone()
+        };
+        assert_eq!(
+            before.attributes.diff(&after),
+            vec![
+                FieldChange {
+                    field: "currently_entitled_amount_cents",
+                    old: "500".to_string(),
+                    new: "1000".to_string(),
+                },
+                FieldChange {
+                    field: "patron_status",
+                    old: "active_patron".to_string(),
+                    new: "declined_patron".to_string(),
+                },
+            ]
+        );
+        assert!(before.attributes.diff(&before.attributes).is_empty());
+        let cleared = MemberAttributes {
+            next_charge_date: None,
+            ..before.attributes.clone()
+        };
+        assert_eq!(
+            before.attributes.diff(&cleared),
+            vec![FieldChange {
+                field: "next_charge_date",
+                old: "2023-05-01 00:00:00 UTC".to_string(),
+                new: String::new(),
+            }]
+        );
+    }
+
+    #[test]
+    fn unknown_attributes_are_ignored_or_captured() {
+        let member: Member = serde_json::from_str(MEMBER).unwrap();
+        assert_eq!(member.attributes.currently_entitled_amount_cents, 500);
+        #[cfg(feature = "capture-unknown")]
+        {
+            assert_eq!(member.attributes.extra.len(), 1);
+            assert_eq!(
+                member.attributes.extra["brand_new_field"],
+                serde_json::json!({"nested": true})
+            );
+        }
+    }
+
+    #[test]
+    fn classify_pledge_change_follows_is_supporting() {
+        use PatronStatus::*;
+        let cases = [
+            (None, 0, Some(ActivePatron), 500, PledgeChange::New),
+            (None, 0, Some(DeclinedPatron), 500, PledgeChange::New),
+            (None, 0, None, 0, PledgeChange::Unchanged),
+            (
+                Some(ActivePatron),
+                500,
+                Some(ActivePatron),
+                1000,
+                PledgeChange::Upgraded,
+            ),
+            (
+                Some(ActivePatron),
+                1000,
+                Some(ActivePatron),
+                500,
+                PledgeChange::Downgraded,
+            ),
+            (
+                Some(ActivePatron),
+                500,
+                Some(ActivePatron),
+                500,
+                PledgeChange::Unchanged,
+            ),
+            (
+                Some(ActivePatron),
+                500,
+                Some(DeclinedPatron),
+                500,
+                PledgeChange::Unchanged,
+            ),
+            (
+                Some(DeclinedPatron),
+                500,
+                Some(ActivePatron),
+                500,
+                PledgeChange::Unchanged,
+            ),
+            (
+                Some(ActivePatron),
+                500,
+                Some(FormerPatron),
+                0,
+                PledgeChange::Churned,
+            ),
+            (
+                Some(DeclinedPatron),
+                500,
+                Some(FormerPatron),
+                0,
+                PledgeChange::Churned,
+            ),
+            (Some(DeclinedPatron), 500, None, 0, PledgeChange::Churned),
+            (
+                Some(FormerPatron),
+                0,
+                Some(ActivePatron),
+                500,
+                PledgeChange::Reactivated,
+            ),
+            (
+                Some(FormerPatron),
+                0,
+                Some(FormerPatron),
+                0,
+                PledgeChange::Unchanged,
+            ),
+            (
+                Some(Unknown),
+                0,
+                Some(ActivePatron),
+                500,
+                PledgeChange::Reactivated,
+            ),
+        ];
+        for (prev_status, prev_cents, curr_status, curr_cents, expected) in cases {
+            let prev = MemberAttributes {
+                patron_status: prev_status,
+                currently_entitled_amount_cents: prev_cents,
+                ..Default::default()
+            };
+            let curr = MemberAttributes {
+                patron_status: curr_status,
+                currently_entitled_amount_cents: curr_cents,
+                ..Default::default()
+            };
+            assert_eq!(
+                classify_pledge_change(&prev, &curr),
+                expected,
+                "{prev_status:?} -> {curr_status:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn success_status_with_errors_is_an_error() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            r#"{"errors":[{"code":1,"detail":"boom"},{"code":"not-a-number"}]}"#,
+        )])
+        .await;
+        let api = api_for(&server);
+        match api.webhooks().await.unwrap_err() {
+            PatreonError::PatreonApi(status, errors) => {
+                assert_eq!(status, reqwest::StatusCode::OK);
+                assert_eq!(errors.len(), 2);
+                assert_eq!(errors[0].code, Some(1));
+                assert_eq!(errors[0].detail, "boom");
+                assert_eq!(errors[1].detail, r#"{"code":"not-a-number"}"#);
+            }
+            err => panic!("unexpected error {err:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn success_status_with_empty_errors_is_ok() {
+        let server =
+            MockServer::start(vec![MockResponse::json(200, r#"{"data":[],"errors":[]}"#)]).await;
+        let api = api_for(&server);
+        assert_eq!(api.webhooks().await.unwrap(), r#"{"data":[],"errors":[]}"#);
+    }
+
+    #[tokio::test]
+    async fn api_request_sends_default_headers() {
+        let server = MockServer::start(vec![MockResponse::json(200, "{}")]).await;
+        let mut headers = HeaderMap::new();
+        headers.insert("x-gateway-key", "gateway-secret".parse().unwrap());
+        headers.insert(AUTHORIZATION, "Bearer other".parse().unwrap());
+        let api = PatreonApi {
+            access_token: "token".to_string(),
+            ..Default::default()
+        }
+        .with_base_uri(server.uri.as_str())
+        .unwrap()
+        .with_default_headers(headers);
+        assert!(!format!("{api:?}").contains("gateway-secret"));
+        api.webhooks().await.unwrap();
+        let request = &server.requests()[0];
+        assert!(request.contains("x-gateway-key: gateway-secret\r\n"));
+        assert!(request.contains("authorization: Bearer token\r\n"));
+        assert!(!request.contains("Bearer other"));
+    }
+
+    #[test]
+    fn max_concurrency_rejects_out_of_range() {
+        assert!(PatreonApi::default().with_max_concurrency(0).is_err());
+        assert!(PatreonApi::default()
+            .with_max_concurrency(Semaphore::MAX_PERMITS + 1)
+            .is_err());
+        assert!(PatreonApi::default()
+            .with_max_concurrency(Semaphore::MAX_PERMITS)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn max_concurrency_serializes_requests() {
+        let delay = Duration::from_millis(100);
+        let server = MockServer::start(vec![
+            MockResponse::json(200, "{}").delayed(delay),
+            MockResponse::json(200, "{}").delayed(delay),
+        ])
+        .await;
+        let api = api_for(&server).with_max_concurrency(1).unwrap();
+        let (first, second) = tokio::join!(api.webhooks(), api.webhooks());
+        first.unwrap();
+        second.unwrap();
+        assert_eq!(server.requests().len(), 2);
+        assert_eq!(server.max_in_flight(), 1);
+    }
+
+    #[tokio::test]
+    async fn proxy_survives_accept_invalid_certs() {
+        let proxy = MockServer::start(vec![MockResponse::json(200, "{}")]).await;
+        let api = PatreonApi::default()
+            .with_base_uri("http://patreon.invalid")
+            .unwrap()
+            .with_client_options(
+                ClientOptions::default()
+                    .with_proxy(proxy.uri.as_str())
+                    .with_danger_accept_invalid_certs(true),
+            )
+            .unwrap();
+        assert!(api.client_options.danger_accept_invalid_certs);
+        api.webhooks().await.unwrap();
+        assert!(proxy.requests()[0]
+            .starts_with("GET http://patreon.invalid/api/oauth2/v2/webhooks HTTP/1.1"));
+    }
+
+    #[tokio::test]
+    async fn campaign_members_count_reads_pagination_total() {
+        let body = format!(r#"{{"data":[{MEMBER}],"meta":{{"pagination":{{"total":42}}}}}}"#);
+        let server = MockServer::start(vec![MockResponse::json(200, body.as_str())]).await;
+        assert_eq!(
+            api_for(&server)
+                .campaign_members_count("c-1")
+                .await
+                .unwrap(),
+            42
+        );
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0]
+            .starts_with("GET /api/oauth2/v2/campaigns/c-1/members?page%5Bcount%5D=1 HTTP/1.1"));
+    }
+
+    #[tokio::test]
+    async fn campaign_members_count_falls_back_to_patron_count() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, r#"{"data":[]}"#),
+            MockResponse::json(
+                200,
+                r#"{"data":{"type":"campaign","id":"c-1","attributes":{"patron_count":"7"}}}"#,
+            ),
+        ])
+        .await;
+        assert_eq!(
+            api_for(&server)
+                .campaign_members_count("c-1")
+                .await
+                .unwrap(),
+            7
+        );
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].starts_with(
+            "GET /api/oauth2/v2/campaigns/c-1?fields%5Bcampaign%5D=patron_count HTTP/1.1"
+        ));
+    }
+
+    #[test]
+    fn debug_redacts_access_token() {
+        let api = PatreonApi {
+            access_token: "real-access-token".to_string(),
+            ..Default::default()
+        };
+        let debug = format!("{api:?}");
+        assert!(!debug.contains("real-access-token"));
+        assert!(debug.contains(r#"access_token: "***""#));
+    }
+
+    #[test]
+    fn unrecognized_statuses_fall_back() {
+        assert_eq!(
+            serde_json::from_str::<PatronStatus>(r#""paused_patron""#).unwrap(),
+            PatronStatus::Unknown
+        );
+        assert_eq!(
+            serde_json::from_str::<LastChrgeStatus>(r#""Chargeback""#).unwrap(),
+            LastChrgeStatus::Other
+        );
+        let member: Member = serde_json::from_str(
+            MEMBER
+                .replace("active_patron", "paused_patron")
+                .replace(r#""Paid""#, r#""Chargeback""#)
+                .as_str(),
+        )
+        .unwrap();
+        assert_eq!(member.attributes.patron_status, Some(PatronStatus::Unknown));
+        assert_eq!(
+            member.attributes.last_charge_status,
+            Some(LastChrgeStatus::Other)
+        );
+    }
+
+    #[test]
+    fn invalid_proxy_is_an_error() {
+        assert!(PatreonApi::default()
+            .with_client_options(ClientOptions::default().with_proxy("not a url"))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn active_memberships_filters_former_patrons() {
+        let former = MEMBER
+            .replace(r#""id": "m-1""#, r#""id": "m-2""#)
+            .replace("active_patron", "former_patron");
+        let body = format!(r#"{{"data":{USER},"included":[{MEMBER},{former}]}}"#);
+        let server = MockServer::start(vec![
+            MockResponse::json(200, body.as_str()),
+            MockResponse::json(200, body.as_str()),
+        ])
+        .await;
+        let api = api_for(&server);
+        let memberships = api.memberships().await.unwrap();
+        assert_eq!(memberships.len(), 2);
+        let active = api.active_memberships().await.unwrap();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].id, "m-1");
+        assert!(server.requests()[0].contains("include=memberships"));
+    }
+
+    #[tokio::test]
+    async fn find_campaign_by_vanity_ignores_case() {
+        let campaigns = [campaign_json("1"), campaign_json("2")];
+        let server = MockServer::start(vec![
+            identity_with_campaigns(&campaigns),
+            identity_with_campaigns(&campaigns),
+        ])
+        .await;
+        let api = api_for(&server);
+        let found = api.find_campaign_by_vanity("CREATOR-1").await.unwrap();
+        assert_eq!(found.map(|campaign| campaign.id), Some("1".to_string()));
+        assert!(api
+            .find_campaign_by_vanity("someone-else")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn campaign_publication_and_nsfw() {
+        let published_at = Some(Utc::now());
+        // (published_at, is_nsfw, allow_nsfw, is_published, is_displayable)
+        let cases = [
+            (published_at, false, false, true, true),
+            (published_at, false, true, true, true),
+            (published_at, true, false, true, false),
+            (published_at, true, true, true, true),
+            (None, false, true, false, false),
+            (None, true, true, false, false),
+        ];
+        for (published_at, is_nsfw, allow_nsfw, is_published, is_displayable) in cases {
+            let campaign = CampaignAttributes {
+                published_at,
+                is_nsfw,
+                ..Default::default()
+            };
+            assert_eq!(campaign.is_published(), is_published);
+            assert_eq!(
+                campaign.is_displayable(allow_nsfw),
+                is_displayable,
+                "{published_at:?} nsfw {is_nsfw} allow {allow_nsfw}"
+            );
+        }
+    }
+
+    #[test]
+    fn rss_url_requires_rss_and_vanity() {
+        let campaign = CampaignAttributes {
+            has_rss: true,
+            vanity: "creator-1".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            campaign.rss_url().as_deref(),
+            Some("https://www.patreon.com/rss/creator-1")
+        );
+        let no_rss = CampaignAttributes {
+            has_rss: false,
+            ..campaign.clone()
+        };
+        assert_eq!(no_rss.rss_url(), None);
+        let no_vanity = CampaignAttributes {
+            vanity: String::new(),
+            ..campaign
+        };
+        assert_eq!(no_vanity.rss_url(), None);
+    }
+
+    #[test]
+    // ..Default::default() fills `extra` under capture-unknown
+    #[allow(clippy::needless_update)]
+    fn post_round_trips_through_serde() {
+        let post = Post {
+            document_type: "post".to_string(),
+            id: "81234567".to_string(),
+            attributes: PostAttributes {
+                title: Some("Chapter 12".to_string()),
+                content: Some("<p>New chapter is up</p>".to_string()),
+                is_public: true,
+                is_paid: true,
+                published_at: Some(
+                    chrono::TimeZone::with_ymd_and_hms(&Utc, 2023, 4, 5, 6, 7, 8).unwrap(),
+                ),
+                url: "/posts/chapter-12-81234567".to_string(),
+                embed_data: Some(serde_json::json!({"provider": "YouTube", "html": "<iframe/>"})),
+                embed_url: Some("https://www.youtube.com/watch?v=abc".to_string()),
+                app_id: Some(42),
+                app_status: Some("published".to_string()),
+                ..Default::default()
+            },
+            links: Some(DocumentLinks {
+                self_link: Some("https://www.patreon.com/api/oauth2/v2/posts/81234567".to_string()),
+            }),
+        };
+        let json = serde_json::to_string(&post).unwrap();
+        assert_eq!(serde_json::from_str::<Post>(json.as_str()).unwrap(), post);
+    }
+
+    #[test]
+    fn self_link_reads_links_self() {
+        let user: User = serde_json::from_str(USER).unwrap();
+        assert_eq!(
+            user.self_link(),
+            Some("https://www.patreon.com/api/user/101")
+        );
+        let member: Member = serde_json::from_str(MEMBER).unwrap();
+        assert_eq!(member.self_link(), None);
+    }
+
+    #[test]
+    fn patron_status_predicates() {
+        use PatronStatus::*;
+        // (status, is_active, is_former, is_supporting)
+        let cases = [
+            (ActivePatron, true, false, true),
+            (DeclinedPatron, false, false, true),
+            (FormerPatron, false, true, false),
+            (Unknown, false, false, false),
+        ];
+        for (status, is_active, is_former, is_supporting) in cases {
+            assert_eq!(status.is_active(), is_active, "{status:?}");
+            assert_eq!(status.is_former(), is_former, "{status:?}");
+            assert_eq!(status.is_supporting(), is_supporting, "{status:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn from_tokens_sends_bearer_header() {
+        let tokens = TokensResponse {
+            access_token: "access".to_string(),
+            refresh_token: "refresh".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(tokens.bearer_header(), "Bearer access");
+        let server = MockServer::start(vec![MockResponse::json(200, "{}")]).await;
+        let api = PatreonApi::from_tokens(&tokens)
+            .with_base_uri(server.uri.as_str())
+            .unwrap();
+        assert_eq!(api.access_token, "access");
+        api.webhooks().await.unwrap();
+        assert!(server.requests()[0]
+            .contains(format!("authorization: {}\r\n", tokens.bearer_header()).as_str()));
+    }
+}
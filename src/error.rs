@@ -8,9 +8,13 @@ pub type PatreonResult<A> = std::result::Result<A, PatreonError>;
 pub enum PatreonError {
     Reqwest(reqwest::Error),
     SerdeJson(serde_json::Error),
+    Deserialize(serde_json::Error, String),
     PatreonOAuth(StatusCode, String),
     PatreonApi(StatusCode, Vec<ApiError>),
+    Url(url::ParseError),
     Message(String),
+    NoCampaign,
+    AmbiguousCampaign(usize),
 }
 
 impl Display for PatreonError {
@@ -18,6 +22,9 @@ impl Display for PatreonError {
         match self {
             PatreonError::Reqwest(err) => Display::fmt(err, f),
             PatreonError::SerdeJson(err) => Display::fmt(err, f),
+            PatreonError::Deserialize(err, body) => {
+                write!(f, "Deserialize ( {err} , body : {body} )")
+            }
             PatreonError::PatreonOAuth(code, msg) => {
                 f.write_str(format!("PatreonOAuth{{ {code} : {msg} }}").as_str())
             }
@@ -30,14 +37,28 @@ impl Display for PatreonError {
                 f.write_str(" ] }")?;
                 Ok(())
             }
+            PatreonError::Url(err) => Display::fmt(err, f),
             PatreonError::Message(msg) => {
                 write!(f, "Message ( {msg} ) ,")
             }
+            PatreonError::NoCampaign => f.write_str("NoCampaign"),
+            PatreonError::AmbiguousCampaign(count) => {
+                write!(f, "AmbiguousCampaign ( {count} campaigns )")
+            }
         }
     }
 }
 
-impl std::error::Error for PatreonError {}
+impl std::error::Error for PatreonError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PatreonError::Reqwest(err) => Some(err),
+            PatreonError::SerdeJson(err) | PatreonError::Deserialize(err, _) => Some(err),
+            PatreonError::Url(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl From<reqwest::Error> for PatreonError {
     fn from(value: reqwest::Error) -> Self {
@@ -51,7 +72,29 @@ impl From<serde_json::Error> for PatreonError {
     }
 }
 
+impl From<url::ParseError> for PatreonError {
+    fn from(value: url::ParseError) -> Self {
+        Self::Url(value)
+    }
+}
+
+const BODY_SNIPPET_CHARS: usize = 512;
+
+pub(crate) fn from_json_slice<T: for<'de> serde::Deserialize<'de>>(
+    body: &[u8],
+) -> PatreonResult<T> {
+    serde_json::from_slice(body).map_err(|err| {
+        let body = String::from_utf8_lossy(body);
+        let mut snippet: String = body.chars().take(BODY_SNIPPET_CHARS).collect();
+        if body.chars().nth(BODY_SNIPPET_CHARS).is_some() {
+            snippet.push_str("...");
+        }
+        PatreonError::Deserialize(err, snippet)
+    })
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct ApiError {
     pub code: Option<i64>,
     pub code_name: String,
@@ -75,3 +118,41 @@ impl Display for ApiError {
 }
 
 impl std::error::Error for ApiError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_json_slice_truncates_long_bodies() {
+        let body = "x".repeat(BODY_SNIPPET_CHARS + 10);
+        match from_json_slice::<ApiError>(body.as_bytes()).unwrap_err() {
+            PatreonError::Deserialize(_, snippet) => {
+                assert_eq!(snippet.len(), BODY_SNIPPET_CHARS + 3);
+                assert!(snippet.ends_with("..."));
+            }
+            err => panic!("unexpected error {err:?}"),
+        }
+    }
+
+    #[test]
+    fn source_is_set_for_wrapped_errors() {
+        use std::error::Error;
+        let serde_err = || serde_json::from_str::<ApiError>("{").unwrap_err();
+        let url_err = url::Url::parse("not a uri").unwrap_err();
+        assert!(PatreonError::SerdeJson(serde_err()).source().is_some());
+        assert!(PatreonError::Deserialize(serde_err(), "{".to_string())
+            .source()
+            .is_some());
+        assert!(PatreonError::Url(url_err).source().is_some());
+        assert!(PatreonError::Message("message".to_string())
+            .source()
+            .is_none());
+        assert!(PatreonError::NoCampaign.source().is_none());
+        assert!(
+            PatreonError::PatreonApi(StatusCode::OK, vec![ApiError::default()])
+                .source()
+                .is_none()
+        );
+    }
+}
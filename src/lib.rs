@@ -1,10 +1,17 @@
 pub use api::*;
+pub use client_options::*;
 pub use error::*;
 pub use oauth2::*;
 pub use webhook::*;
 
 pub mod api;
+pub mod client_options;
 mod compile_rules;
 pub mod error;
 pub mod oauth2;
+pub mod prelude;
+mod serde_helpers;
+#[cfg(test)]
+mod test_utils;
+mod uri;
 pub mod webhook;
@@ -1,24 +1,99 @@
-use crate::{PatreonError, PatreonResult};
+use crate::client_options::{header_names, request_default_headers};
+use crate::error::from_json_slice;
+use crate::uri::{endpoint, parse_base_uri};
+use crate::{ClientOptions, PatreonError, PatreonResult};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use reqwest::header::HeaderMap;
 use reqwest::StatusCode;
 use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
 use std::sync::Arc;
+use std::time::Duration;
 use url::Url;
 
 static BASE_URI: &str = "https://www.patreon.com";
 
-#[derive(Debug, Default)]
 pub struct PatreonOAuth {
     pub client_id: String,
     pub client_secret: String,
     pub redirect_uri: String,
     pub agent: Arc<reqwest::Client>,
+    pub client_options: ClientOptions,
+    pub base_uri: Url,
+    pub retry_policy: RetryPolicy,
+    pub default_headers: HeaderMap,
+}
+
+impl Default for PatreonOAuth {
+    fn default() -> Self {
+        Self {
+            client_id: String::default(),
+            client_secret: String::default(),
+            redirect_uri: String::default(),
+            agent: Arc::default(),
+            client_options: ClientOptions::default(),
+            base_uri: Url::parse(BASE_URI).unwrap(),
+            retry_policy: RetryPolicy::default(),
+            default_headers: HeaderMap::default(),
+        }
+    }
+}
+
+impl Debug for PatreonOAuth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PatreonOAuth")
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"***")
+            .field("redirect_uri", &self.redirect_uri)
+            .field("agent", &self.agent)
+            .field("client_options", &self.client_options)
+            .field("base_uri", &self.base_uri)
+            .field("retry_policy", &self.retry_policy)
+            .field("default_headers", &header_names(&self.default_headers))
+            .finish()
+    }
 }
 
 impl PatreonOAuth {
+    // a public client (mobile / SPA) has no client_secret and never sends one
+    pub fn public(client_id: impl Into<String>, redirect_uri: impl Into<String>) -> Self {
+        Self {
+            client_id: client_id.into(),
+            redirect_uri: redirect_uri.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.client_secret.is_empty()
+    }
+
+    pub fn with_base_uri(mut self, base_uri: &str) -> PatreonResult<Self> {
+        self.base_uri = parse_base_uri(base_uri)?;
+        Ok(self)
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_default_headers(mut self, default_headers: HeaderMap) -> Self {
+        self.default_headers = default_headers;
+        self
+    }
+
+    pub fn with_client_options(mut self, client_options: ClientOptions) -> PatreonResult<Self> {
+        self.agent = Arc::new(client_options.build()?);
+        self.client_options = client_options;
+        Ok(self)
+    }
+
     pub fn get_authorization_url(&self, scope: &str, state: &str) -> String {
-        let mut url = Url::parse(BASE_URI).unwrap();
-        url.set_path("/oauth2/authorize");
+        let mut url = endpoint(&self.base_uri, "/oauth2/authorize");
         if !scope.is_empty() {
             url.query_pairs_mut().append_pair("scope", scope);
         }
@@ -33,25 +108,88 @@ impl PatreonOAuth {
         url.to_string()
     }
 
+    pub fn authorization_url_builder(&self, scopes: &[&str]) -> Url {
+        let mut url = endpoint(&self.base_uri, "/oauth2/authorize");
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", self.client_id.as_str())
+            .append_pair("redirect_uri", self.redirect_uri.as_str());
+        if !scopes.is_empty() {
+            url.query_pairs_mut()
+                .append_pair("scope", scopes.join(" ").as_str());
+        }
+        url
+    }
+
+    // PKCE (S256), the matching code_verifier goes to get_tokens_with_verifier
+    pub fn authorization_url_with_pkce(
+        &self,
+        scopes: &[&str],
+        code_verifier: &str,
+    ) -> PatreonResult<Url> {
+        let mut url = self.authorization_url_builder(scopes);
+        url.query_pairs_mut()
+            .append_pair(
+                "code_challenge",
+                pkce_code_challenge(code_verifier)?.as_str(),
+            )
+            .append_pair("code_challenge_method", "S256");
+        Ok(url)
+    }
+
     pub async fn get_tokens(&self, code: &str) -> PatreonResult<TokensResponse> {
+        self.exchange_code(code, None).await
+    }
+
+    pub async fn get_tokens_with_verifier(
+        &self,
+        code: &str,
+        code_verifier: &str,
+    ) -> PatreonResult<TokensResponse> {
+        check_code_verifier(code_verifier)?;
+        self.exchange_code(code, Some(code_verifier)).await
+    }
+
+    async fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: Option<&str>,
+    ) -> PatreonResult<TokensResponse> {
         self.parse_token_request(&{
             let mut params = HashMap::new();
             params.insert("grant_type", "authorization_code");
             params.insert("code", code);
             params.insert("client_id", self.client_id.as_str());
-            params.insert("client_secret", self.client_secret.as_str());
+            if !self.is_public() {
+                params.insert("client_secret", self.client_secret.as_str());
+            }
+            if let Some(code_verifier) = code_verifier {
+                params.insert("code_verifier", code_verifier);
+            }
             params.insert("redirect_uri", self.redirect_uri.as_str());
             params
         })
         .await
     }
 
+    pub async fn get_tokens_checked(
+        &self,
+        code: &str,
+        requested: &[&str],
+    ) -> PatreonResult<(TokensResponse, Vec<String>)> {
+        let tokens = self.get_tokens(code).await?;
+        let missing = tokens.missing_scopes(requested);
+        Ok((tokens, missing))
+    }
+
     pub async fn refresh_tokens(&self, refresh_token: &str) -> PatreonResult<TokensResponse> {
         self.parse_token_request(&{
             let mut params = HashMap::new();
             params.insert("grant_type", "refresh_token");
             params.insert("client_id", self.client_id.as_str());
-            params.insert("client_secret", self.client_secret.as_str());
+            if !self.is_public() {
+                params.insert("client_secret", self.client_secret.as_str());
+            }
             params.insert("refresh_token", refresh_token);
             params
         })
@@ -62,16 +200,79 @@ impl PatreonOAuth {
         &self,
         params: &HashMap<&str, &str>,
     ) -> PatreonResult<TokensResponse> {
-        let mut url = Url::parse(BASE_URI).unwrap();
-        url.set_path("/api/oauth2/token");
-        let response = self.agent.post(url).form(params).send().await?;
-        let status = response.status();
-        let text = response.text().await?;
-        de_response(status, text)
+        let url = endpoint(&self.base_uri, "/api/oauth2/token");
+        let default_headers = request_default_headers(&self.default_headers);
+        let mut retries = 0;
+        loop {
+            let response = self
+                .agent
+                .post(url.clone())
+                .headers(default_headers.clone())
+                .form(params)
+                .send()
+                .await?;
+            let status = response.status();
+            // 4xx such as invalid_grant / invalid_request are terminal, only 5xx is retried
+            if status.is_server_error() && retries < self.retry_policy.max_retries {
+                let backoff = self.retry_policy.delay(retries);
+                retries += 1;
+                tracing::debug!("TOKEN RESPONSE : {status} : retry {retries} in {backoff:?}");
+                tokio::time::sleep(backoff).await;
+                continue;
+            }
+            let text = response.text().await?;
+            return de_response(status, text);
+        }
+    }
+}
+
+// RFC 7636 : 43 to 128 characters of [A-Z] / [a-z] / [0-9] / "-" / "." / "_" / "~"
+fn check_code_verifier(code_verifier: &str) -> PatreonResult<()> {
+    let valid_chars = code_verifier
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | '~'));
+    if !valid_chars || !(43..=128).contains(&code_verifier.len()) {
+        return Err(PatreonError::Message(
+            "code verifier must be 43 to 128 unreserved characters".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn pkce_code_challenge(code_verifier: &str) -> PatreonResult<String> {
+    check_code_verifier(code_verifier)?;
+    Ok(URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    // doubled after every retry
+    pub backoff: Duration,
+    // upper bound of a single wait, however large max_retries is
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
     }
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+impl RetryPolicy {
+    pub fn delay(&self, retry: u32) -> Duration {
+        2u32.checked_pow(retry)
+            .and_then(|factor| self.backoff.checked_mul(factor))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TokensResponse {
     pub access_token: String,
     pub expires_in: u64,
@@ -82,6 +283,34 @@ pub struct TokensResponse {
     pub version: String,
 }
 
+impl Debug for TokensResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokensResponse")
+            .field("access_token", &"***")
+            .field("expires_in", &self.expires_in)
+            .field("token_type", &self.token_type)
+            .field("scope", &self.scope)
+            .field("refresh_token", &"***")
+            .field("version", &self.version)
+            .finish()
+    }
+}
+
+impl TokensResponse {
+    pub fn bearer_header(&self) -> String {
+        format!("Bearer {}", self.access_token)
+    }
+
+    pub fn missing_scopes(&self, requested: &[&str]) -> Vec<String> {
+        let granted: Vec<&str> = self.scope.split_whitespace().collect();
+        requested
+            .iter()
+            .filter(|scope| !granted.contains(scope))
+            .map(|scope| scope.to_string())
+            .collect()
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct ErrorResponse {
     pub error: String,
@@ -92,11 +321,289 @@ fn de_response<T: for<'de> serde::Deserialize<'de>>(
     text: String,
 ) -> PatreonResult<T> {
     if status.is_success() {
+        // a token body carries access_token / refresh_token, never echo it into the error
         Ok(serde_json::from_str(text.as_str())?)
     } else {
         Err(PatreonError::PatreonOAuth(
             status,
-            serde_json::from_str::<ErrorResponse>(text.as_str())?.error,
+            from_json_slice::<ErrorResponse>(text.as_bytes())?.error,
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockResponse, MockServer};
+
+    const TOKENS: &str = r#"{"access_token":"a","refresh_token":"r","expires_in":3600,"token_type":"Bearer","scope":"identity"}"#;
+
+    fn oauth_for(server: &MockServer) -> PatreonOAuth {
+        PatreonOAuth::default()
+            .with_base_uri(server.uri.as_str())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn malformed_token_response_does_not_leak_tokens() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            r#"{"access_token":"SECRET_ACCESS","refresh_token":"SECRET_REFRESH","expires_in":"soon"}"#,
+        )])
+        .await;
+        let oauth = oauth_for(&server);
+        let err = oauth.get_tokens("code").await.unwrap_err();
+        assert!(matches!(err, PatreonError::SerdeJson(_)));
+        let message = format!("{err} {err:?}");
+        assert!(!message.contains("SECRET_ACCESS"));
+        assert!(!message.contains("SECRET_REFRESH"));
+        assert!(server.requests()[0].starts_with("POST /api/oauth2/token "));
+    }
+
+    #[tokio::test]
+    async fn token_request_keeps_base_path_prefix() {
+        let server = MockServer::start(vec![MockResponse::json(200, TOKENS)]).await;
+        let oauth = PatreonOAuth::default()
+            .with_base_uri(format!("{}/patreon", server.uri).as_str())
+            .unwrap();
+        let tokens = oauth.get_tokens("code").await.unwrap();
+        assert_eq!(tokens.access_token, "a");
+        let request = &server.requests()[0];
+        assert!(request.starts_with("POST /patreon/api/oauth2/token "));
+        assert!(request.contains("code=code"));
+    }
+
+    #[test]
+    fn retry_delay_is_capped_without_overflow() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.delay(0), Duration::from_millis(500));
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(10), Duration::from_secs(30));
+        assert_eq!(policy.delay(31), Duration::from_secs(30));
+        assert_eq!(policy.delay(32), Duration::from_secs(30));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(30));
+        let policy = RetryPolicy {
+            backoff: Duration::MAX,
+            max_backoff: Duration::MAX,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(1), Duration::MAX);
+    }
+
+    fn fast_retry() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            backoff: Duration::from_millis(1),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn token_request_retries_server_errors() {
+        let server = MockServer::start(vec![
+            MockResponse::json(503, r#"{"error":"unavailable"}"#),
+            MockResponse::json(200, TOKENS),
+        ])
+        .await;
+        let oauth = oauth_for(&server).with_retry_policy(fast_retry());
+        let tokens = oauth.refresh_tokens("r").await.unwrap();
+        assert_eq!(tokens.access_token, "a");
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn token_request_does_not_retry_invalid_grant() {
+        let server = MockServer::start(vec![
+            MockResponse::json(400, r#"{"error":"invalid_grant"}"#),
+            MockResponse::json(200, "{}"),
+        ])
+        .await;
+        let oauth = oauth_for(&server).with_retry_policy(fast_retry());
+        match oauth.refresh_tokens("r").await.unwrap_err() {
+            PatreonError::PatreonOAuth(status, error) => {
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert_eq!(error, "invalid_grant");
+            }
+            err => panic!("unexpected error {err:?}"),
+        }
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    // challenge computed independently as base64url(sha256(verifier)) without padding
+    const VERIFIER: &str = "dBjftJeZ4CVP-mJ92TuMp-2CKqZ8FfhvCvSj23avwmU";
+    const CHALLENGE: &str = "btlCy_2H6xkHCmzPcq4QxcfMsFZw-VbIKDkjch0cQg0";
+
+    #[test]
+    fn pkce_challenge_is_s256_of_verifier() {
+        assert_eq!(pkce_code_challenge(VERIFIER).unwrap(), CHALLENGE);
+        assert!(pkce_code_challenge("too-short").is_err());
+        let url = PatreonOAuth::public("id", "https://example.com/cb")
+            .authorization_url_with_pkce(&["identity"], VERIFIER)
+            .unwrap();
+        let pairs: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(pairs["code_challenge"], CHALLENGE);
+        assert_eq!(pairs["code_challenge_method"], "S256");
+        assert_eq!(pairs["client_id"], "id");
+    }
+
+    fn token_form(request: &str) -> HashMap<String, String> {
+        let body = request.split_once("\r\n\r\n").unwrap().1;
+        url::form_urlencoded::parse(body.as_bytes())
+            .into_owned()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn code_verifier_is_sent_with_and_without_secret() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, TOKENS),
+            MockResponse::json(200, TOKENS),
+            MockResponse::json(200, TOKENS),
+        ])
+        .await;
+        let public = PatreonOAuth::public("id", "https://example.com/cb")
+            .with_base_uri(server.uri.as_str())
+            .unwrap();
+        let confidential = PatreonOAuth {
+            client_secret: "secret".to_string(),
+            ..PatreonOAuth::public("id", "https://example.com/cb")
+        }
+        .with_base_uri(server.uri.as_str())
+        .unwrap();
+        public
+            .get_tokens_with_verifier("code", VERIFIER)
+            .await
+            .unwrap();
+        confidential
+            .get_tokens_with_verifier("code", VERIFIER)
+            .await
+            .unwrap();
+        confidential.get_tokens("code").await.unwrap();
+
+        let requests = server.requests();
+        let form = token_form(&requests[0]);
+        assert_eq!(form["code_verifier"], VERIFIER);
+        assert!(!form.contains_key("client_secret"));
+        let form = token_form(&requests[1]);
+        assert_eq!(form["code_verifier"], VERIFIER);
+        assert_eq!(form["client_secret"], "secret");
+        let form = token_form(&requests[2]);
+        assert!(!form.contains_key("code_verifier"));
+        assert_eq!(form["client_secret"], "secret");
+        assert_eq!(form["grant_type"], "authorization_code");
+    }
+
+    #[tokio::test]
+    async fn token_request_sends_default_headers() {
+        let server = MockServer::start(vec![MockResponse::json(200, TOKENS)]).await;
+        let mut headers = HeaderMap::new();
+        headers.insert("x-gateway-key", "gateway-secret".parse().unwrap());
+        let oauth = oauth_for(&server).with_default_headers(headers);
+        assert!(!format!("{oauth:?}").contains("gateway-secret"));
+        oauth.refresh_tokens("r").await.unwrap();
+        assert!(server.requests()[0].contains("x-gateway-key: gateway-secret\r\n"));
+    }
+
+    #[test]
+    fn missing_scopes_lists_what_was_not_granted() {
+        let tokens = TokensResponse {
+            scope: "identity  campaigns\tw:campaigns.webhook".to_string(),
+            ..Default::default()
+        };
+        assert!(tokens
+            .missing_scopes(&["identity", "campaigns", "w:campaigns.webhook"])
+            .is_empty());
+        assert_eq!(
+            tokens.missing_scopes(&["identity", "identity[email]", "campaigns.members"]),
+            vec![
+                "identity[email]".to_string(),
+                "campaigns.members".to_string()
+            ]
+        );
+        assert_eq!(
+            TokensResponse::default().missing_scopes(&["identity"]),
+            vec!["identity".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn get_tokens_checked_reports_missing_scopes() {
+        let server = MockServer::start(vec![MockResponse::json(200, TOKENS)]).await;
+        let oauth = oauth_for(&server);
+        let (tokens, missing) = oauth
+            .get_tokens_checked("code", &["identity", "campaigns"])
+            .await
+            .unwrap();
+        assert_eq!(tokens.scope, "identity");
+        assert_eq!(missing, vec!["campaigns".to_string()]);
+    }
+
+    #[test]
+    fn invalid_base_uri_is_rejected() {
+        assert!(PatreonOAuth::default().with_base_uri("not a uri").is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_error_response_keeps_body_snippet() {
+        let server =
+            MockServer::start(vec![MockResponse::json(502, "<html>bad gateway</html>")]).await;
+        let oauth = oauth_for(&server).with_retry_policy(RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        });
+        match oauth.get_tokens("code").await.unwrap_err() {
+            PatreonError::Deserialize(_, body) => assert_eq!(body, "<html>bad gateway</html>"),
+            err => panic!("unexpected error {err:?}"),
+        }
+    }
+
+    #[test]
+    fn debug_redacts_secrets() {
+        let tokens = TokensResponse {
+            access_token: "real-access-token".to_string(),
+            refresh_token: "real-refresh-token".to_string(),
+            ..Default::default()
+        };
+        let debug = format!("{tokens:?}");
+        assert!(!debug.contains("real-access-token"));
+        assert!(!debug.contains("real-refresh-token"));
+        assert!(debug.contains(r#"access_token: "***""#));
+        assert!(debug.contains(r#"refresh_token: "***""#));
+
+        let oauth = PatreonOAuth {
+            client_secret: "real-client-secret".to_string(),
+            ..PatreonOAuth::public("id", "https://example.com/cb")
+        };
+        let debug = format!("{oauth:?}");
+        assert!(!debug.contains("real-client-secret"));
+        assert!(debug.contains(r#"client_secret: "***""#));
+    }
+
+    #[test]
+    fn invalid_proxy_is_an_error() {
+        assert!(PatreonOAuth::default()
+            .with_client_options(ClientOptions::default().with_proxy("not a url"))
+            .is_err());
+    }
+
+    #[test]
+    fn authorization_url_builder_query_pairs() {
+        let oauth = PatreonOAuth::public("id", "https://example.com/cb");
+        let url = oauth.authorization_url_builder(&["identity", "campaigns"]);
+        assert_eq!(url.path(), "/oauth2/authorize");
+        let pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        let pair = |key: &str, value: &str| (key.to_string(), value.to_string());
+        assert_eq!(
+            pairs,
+            vec![
+                pair("response_type", "code"),
+                pair("client_id", "id"),
+                pair("redirect_uri", "https://example.com/cb"),
+                pair("scope", "identity campaigns"),
+            ]
+        );
+        let url = oauth.authorization_url_builder(&[]);
+        assert!(url.query_pairs().all(|(key, _)| key != "scope"));
+        assert_eq!(url.query_pairs().count(), 3);
+    }
+}
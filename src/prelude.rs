@@ -0,0 +1,9 @@
+pub use crate::api::{
+    Campaign, CampaignAttributes, FieldChange, LastChrgeStatus, Member, MemberAttributes,
+    PatreonApi, PatronStatus, Pledge, PledgeAttributes, PledgeChange, Post, PostAttributes, User,
+    UserAttributes,
+};
+pub use crate::client_options::ClientOptions;
+pub use crate::error::{PatreonError, PatreonResult};
+pub use crate::oauth2::{PatreonOAuth, RetryPolicy, TokensResponse};
+pub use crate::webhook::{Event, SeenStore, Webhook};
@@ -0,0 +1,164 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use serde::de::{Error, Unexpected, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::fmt::Formatter;
+
+// Patreon is not always consistent about number vs string for cents and counts, accept both.
+// Guessing the type needs `deserialize_any`, which only self-describing formats such as JSON
+// support, so binary formats (bincode, postcard) read back the plain i64 they were written as.
+pub(crate) fn de_flexible_i64<'de, D>(deserializer: D) -> Result<i64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    if !deserializer.is_human_readable() {
+        return i64::deserialize(deserializer);
+    }
+
+    struct FlexibleI64;
+
+    impl<'de> Visitor<'de> for FlexibleI64 {
+        type Value = i64;
+
+        fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+            formatter.write_str("an integer or a string containing an integer")
+        }
+
+        fn visit_i64<E: Error>(self, v: i64) -> Result<i64, E> {
+            Ok(v)
+        }
+
+        fn visit_u64<E: Error>(self, v: u64) -> Result<i64, E> {
+            i64::try_from(v).map_err(|_| E::invalid_value(Unexpected::Unsigned(v), &self))
+        }
+
+        fn visit_f64<E: Error>(self, v: f64) -> Result<i64, E> {
+            if v.fract() == 0.0 && v >= i64::MIN as f64 && v <= i64::MAX as f64 {
+                Ok(v as i64)
+            } else {
+                Err(E::invalid_value(Unexpected::Float(v), &self))
+            }
+        }
+
+        fn visit_str<E: Error>(self, v: &str) -> Result<i64, E> {
+            v.trim()
+                .parse()
+                .map_err(|_| E::invalid_value(Unexpected::Str(v), &self))
+        }
+    }
+
+    deserializer.deserialize_any(FlexibleI64)
+}
+
+// RFC3339 (`Z` or `+00:00`), an offset without colon, a naive timestamp or a bare date (midnight UTC).
+fn parse_flexible_datetime(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(value) {
+        return Some(datetime.with_timezone(&Utc));
+    }
+    if let Ok(datetime) = DateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f%z") {
+        return Some(datetime.with_timezone(&Utc));
+    }
+    if let Ok(datetime) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f") {
+        return Some(Utc.from_utc_datetime(&datetime));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    Some(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?))
+}
+
+pub(crate) fn de_flexible_datetime<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_flexible_datetime(value.as_str()).ok_or_else(|| {
+        D::Error::invalid_value(Unexpected::Str(value.as_str()), &"a date or a datetime")
+    })
+}
+
+pub(crate) fn de_flexible_datetime_opt<'de, D>(
+    deserializer: D,
+) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) => parse_flexible_datetime(value.as_str())
+            .map(Some)
+            .ok_or_else(|| {
+                D::Error::invalid_value(Unexpected::Str(value.as_str()), &"a date or a datetime")
+            }),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(serde_derive::Deserialize)]
+    struct Flexible {
+        #[serde(deserialize_with = "de_flexible_i64")]
+        cents: i64,
+    }
+
+    fn flexible(cents: &str) -> serde_json::Result<i64> {
+        serde_json::from_str::<Flexible>(&format!(r#"{{"cents":{cents}}}"#))
+            .map(|flexible| flexible.cents)
+    }
+
+    #[test]
+    fn flexible_numbers() {
+        assert_eq!(flexible("500").unwrap(), 500);
+        assert_eq!(flexible("\" 500 \"").unwrap(), 500);
+        assert_eq!(flexible("-5").unwrap(), -5);
+        assert_eq!(flexible("500.0").unwrap(), 500);
+        assert_eq!(flexible("12345678901").unwrap(), 12345678901);
+    }
+
+    #[test]
+    fn flexible_numbers_reject_non_integers() {
+        for cents in ["500.5", "\"abc\"", "18446744073709551615", "null", "true"] {
+            assert!(flexible(cents).is_err(), "{cents}");
+        }
+    }
+
+    #[test]
+    fn parse_flexible_datetime_formats() {
+        let expected = Utc.with_ymd_and_hms(2023, 4, 5, 6, 7, 8).unwrap();
+        for value in [
+            "2023-04-05T06:07:08Z",
+            "2023-04-05T06:07:08.000+00:00",
+            "2023-04-05T08:07:08+02:00",
+            "2023-04-05T08:07:08+0200",
+            "2023-04-05T06:07:08.000",
+            " 2023-04-05T06:07:08 ",
+        ] {
+            assert_eq!(parse_flexible_datetime(value), Some(expected), "{value}");
+        }
+        assert_eq!(
+            parse_flexible_datetime("2023-04-05"),
+            Some(Utc.with_ymd_and_hms(2023, 4, 5, 0, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn parse_flexible_datetime_rejects_garbage() {
+        for value in ["", "yesterday", "2023-13-01", "05/04/2023"] {
+            assert_eq!(parse_flexible_datetime(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn de_flexible_datetime_opt_accepts_null() {
+        #[derive(serde_derive::Deserialize)]
+        struct Dates {
+            #[serde(default, deserialize_with = "de_flexible_datetime_opt")]
+            date: Option<DateTime<Utc>>,
+        }
+        let parse = |json: &str| serde_json::from_str::<Dates>(json).map(|dates| dates.date);
+        assert_eq!(parse(r#"{"date":null}"#).unwrap(), None);
+        assert_eq!(parse("{}").unwrap(), None);
+        assert!(parse(r#"{"date":"2023-04-05"}"#).unwrap().is_some());
+        assert!(parse(r#"{"date":"garbage"}"#).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use crate::{PatreonError, PatreonResult};
+use url::Url;
+
+pub(crate) fn parse_base_uri(base_uri: &str) -> PatreonResult<Url> {
+    let url = Url::parse(base_uri)?;
+    if url.cannot_be_a_base() {
+        return Err(PatreonError::Message(format!(
+            "base uri cannot be a base : {base_uri}"
+        )));
+    }
+    Ok(url)
+}
+
+// appends path to the base uri's own path, so a base mounted under a prefix keeps it
+pub(crate) fn endpoint(base_uri: &Url, path: &str) -> Url {
+    let mut url = base_uri.clone();
+    url.set_query(None);
+    url.set_fragment(None);
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments
+            .pop_if_empty()
+            .extend(path.trim_start_matches('/').split('/'));
+    }
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoint_keeps_base_path_prefix() {
+        let base = parse_base_uri("http://localhost:8080/patreon").unwrap();
+        assert_eq!(
+            endpoint(&base, "/api/oauth2/token").as_str(),
+            "http://localhost:8080/patreon/api/oauth2/token"
+        );
+        let base = parse_base_uri("http://localhost:8080/patreon/").unwrap();
+        assert_eq!(
+            endpoint(&base, "api/oauth2/v2/identity").as_str(),
+            "http://localhost:8080/patreon/api/oauth2/v2/identity"
+        );
+        let base = parse_base_uri("https://www.patreon.com").unwrap();
+        assert_eq!(
+            endpoint(&base, "/oauth2/authorize").as_str(),
+            "https://www.patreon.com/oauth2/authorize"
+        );
+    }
+
+    #[test]
+    fn parse_base_uri_rejects_invalid() {
+        assert!(matches!(
+            parse_base_uri("not a uri"),
+            Err(PatreonError::Url(_))
+        ));
+        assert!(matches!(
+            parse_base_uri("mailto:someone@example.com"),
+            Err(PatreonError::Message(_))
+        ));
+    }
+}
@@ -1,5 +1,10 @@
 use crate::api::*;
 use crate::error::*;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use std::collections::HashSet;
+use std::fmt::{Debug, Formatter};
+use std::sync::Mutex;
 
 pub enum Event {
     CreatePledge(Pledge),
@@ -11,23 +16,140 @@ pub enum Event {
     CreateMemberPledge(Member),
     UpdateMemberPledge(Member),
     DeleteMemberPledge(Member),
+    PublishPost(Post),
+    UpdatePost(Post),
+    DeletePost(Post),
 }
 
-#[derive(Debug, Default)]
+impl Event {
+    pub fn document_id(&self) -> &str {
+        match self {
+            Event::CreatePledge(pledge)
+            | Event::UpdatePledge(pledge)
+            | Event::DeletePledge(pledge) => pledge.id.as_str(),
+            Event::CreateMember(member)
+            | Event::UpdateMember(member)
+            | Event::DeleteMember(member)
+            | Event::CreateMemberPledge(member)
+            | Event::UpdateMemberPledge(member)
+            | Event::DeleteMemberPledge(member) => member.id.as_str(),
+            Event::PublishPost(post) | Event::UpdatePost(post) | Event::DeletePost(post) => {
+                post.id.as_str()
+            }
+        }
+    }
+}
+
+type HmacMd5 = Hmac<Md5>;
+
+pub trait SeenStore {
+    // check and record in one step, true when key was not seen before
+    fn insert(&self, key: String) -> bool;
+}
+
+impl SeenStore for Mutex<HashSet<String>> {
+    fn insert(&self, key: String) -> bool {
+        self.lock().unwrap().insert(key)
+    }
+}
+
+#[derive(Default)]
 pub struct Webhook {
     pub webhook_secret: String,
+    // still accepted while rotating webhook_secret
+    pub previous_webhook_secrets: Vec<String>,
+    // a binary (non UTF-8) key, used instead of webhook_secret when set
+    pub webhook_secret_bytes: Option<Vec<u8>>,
+}
+
+impl Debug for Webhook {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Webhook")
+            .field("webhook_secret", &"***")
+            .field(
+                "previous_webhook_secrets",
+                &vec!["***"; self.previous_webhook_secrets.len()],
+            )
+            .field(
+                "webhook_secret_bytes",
+                &self.webhook_secret_bytes.as_ref().map(|_| "***"),
+            )
+            .finish()
+    }
 }
 
 impl Webhook {
+    // empty previous secrets are dropped
+    pub fn with_secrets(
+        webhook_secret: impl Into<String>,
+        previous_webhook_secrets: &[&str],
+    ) -> Self {
+        Self {
+            webhook_secret: webhook_secret.into(),
+            previous_webhook_secrets: previous_webhook_secrets
+                .iter()
+                .filter(|secret| !secret.is_empty())
+                .map(|secret| secret.to_string())
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    // keyed exactly like webhook_secret, from_bytes(b"abc") signs the same as "abc"
+    pub fn from_bytes(webhook_secret: &[u8]) -> Self {
+        Self {
+            webhook_secret_bytes: Some(webhook_secret.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    fn current_secret(&self) -> &[u8] {
+        match &self.webhook_secret_bytes {
+            Some(secret) => secret.as_slice(),
+            None => self.webhook_secret.as_bytes(),
+        }
+    }
+
+    // signs with webhook_secret the same way Patreon does, handy for building test requests
+    pub fn sign(&self, body: &[u8]) -> PatreonResult<String> {
+        let mac = Self::mac(self.current_secret(), body)?;
+        Ok(hex::encode(mac.finalize().into_bytes().as_slice()))
+    }
+
     pub fn check_signature(&self, body: &[u8], signature: &str) -> PatreonResult<bool> {
-        use hmac::{Hmac, Mac};
-        use md5::Md5;
-        type HmacMd5 = Hmac<Md5>;
-        let mut mac = HmacMd5::new_from_slice(self.webhook_secret.as_bytes())
-            .map_err(|_| PatreonError::Message("Invalid hmac key length".to_string()))?;
+        // a missing secret is reported whatever the signature looks like
+        Self::check_secret(self.current_secret())?;
+        let signature = match hex::decode(signature) {
+            Ok(signature) => signature,
+            Err(_) => return Ok(false),
+        };
+        // an unset previous secret is skipped, only an unset current secret is an error
+        let previous = self
+            .previous_webhook_secrets
+            .iter()
+            .filter(|secret| !secret.is_empty())
+            .map(|secret| secret.as_bytes());
+        for secret in std::iter::once(self.current_secret()).chain(previous) {
+            let mac = Self::mac(secret, body)?;
+            if mac.verify_slice(signature.as_slice()).is_ok() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn check_secret(secret: &[u8]) -> PatreonResult<()> {
+        if secret.is_empty() {
+            return Err(PatreonError::Message("webhook secret is empty".to_string()));
+        }
+        Ok(())
+    }
+
+    fn mac(secret: &[u8], body: &[u8]) -> PatreonResult<HmacMd5> {
+        Self::check_secret(secret)?;
+        let mut mac = HmacMd5::new_from_slice(secret).expect("HMAC can take key of any size");
         mac.update(body);
-        let local = hex::encode(mac.finalize().into_bytes().as_slice());
-        Ok(local.eq(signature))
+        Ok(mac)
     }
 
     pub fn parse_event(&self, body: &[u8], trigger: &str) -> PatreonResult<Event> {
@@ -41,7 +163,193 @@ impl Webhook {
             "members:pledge:create" => Ok(Event::CreateMemberPledge(DocResponse::parse(body)?)),
             "members:pledge:update" => Ok(Event::UpdateMemberPledge(DocResponse::parse(body)?)),
             "members:pledge:delete" => Ok(Event::DeleteMemberPledge(DocResponse::parse(body)?)),
+            "posts:publish" => Ok(Event::PublishPost(DocResponse::parse(body)?)),
+            "posts:update" => Ok(Event::UpdatePost(DocResponse::parse(body)?)),
+            "posts:delete" => Ok(Event::DeletePost(DocResponse::parse(body)?)),
             _ => Err(PatreonError::Message(format!("unknown trigger: {trigger}"))),
         }
     }
+    // Some(event) for a first delivery, None for a redelivery already recorded in `seen`.
+    // The key includes the signature so distinct updates of the same document are not dropped.
+    pub fn validate_and_parse_dedup(
+        &self,
+        body: &[u8],
+        signature: &str,
+        trigger: &str,
+        seen: &impl SeenStore,
+    ) -> PatreonResult<Option<Event>> {
+        if !self.check_signature(body, signature)? {
+            return Err(PatreonError::Message(
+                "invalid webhook signature".to_string(),
+            ));
+        }
+        let event = self.parse_event(body, trigger)?;
+        // hex is case-insensitive, the same delivery must map to the same key
+        let key = format!(
+            "{trigger}:{}:{}",
+            event.document_id(),
+            signature.to_ascii_lowercase()
+        );
+        if seen.insert(key) {
+            Ok(Some(event))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = br#"{"data":{"id":"1","type":"post","attributes":{"is_public":true,"is_paid":false,"url":"/posts/1"}}}"#;
+
+    #[test]
+    fn rotated_secrets_are_accepted() {
+        let old = Webhook::with_secrets("old", &[]).sign(BODY).unwrap();
+        let new = Webhook::with_secrets("new", &[]).sign(BODY).unwrap();
+        let other = Webhook::with_secrets("other", &[]).sign(BODY).unwrap();
+        let webhook = Webhook::with_secrets("new", &["", "old"]);
+        assert_eq!(webhook.previous_webhook_secrets, vec!["old".to_string()]);
+        assert!(webhook.check_signature(BODY, new.as_str()).unwrap());
+        assert!(webhook.check_signature(BODY, old.as_str()).unwrap());
+        assert!(!webhook.check_signature(BODY, other.as_str()).unwrap());
+        assert!(!webhook.check_signature(BODY, "not hex").unwrap());
+    }
+
+    #[test]
+    fn byte_secrets_are_keyed_like_string_secrets() {
+        assert_eq!(
+            Webhook::from_bytes(b"secret").sign(BODY).unwrap(),
+            Webhook::with_secrets("secret", &[]).sign(BODY).unwrap()
+        );
+        // not valid UTF-8
+        let key: &[u8] = &[0xff, 0x00, 0xfe, 0x80];
+        let webhook = Webhook::from_bytes(key);
+        let signature = webhook.sign(BODY).unwrap();
+        assert!(webhook.check_signature(BODY, signature.as_str()).unwrap());
+        assert!(!Webhook::with_secrets("secret", &[])
+            .check_signature(BODY, signature.as_str())
+            .unwrap());
+        assert!(!format!("{webhook:?}").contains("255"));
+        assert!(Webhook::from_bytes(&[]).sign(BODY).is_err());
+    }
+
+    #[test]
+    fn redelivery_is_deduplicated() {
+        let webhook = Webhook::with_secrets("secret", &[]);
+        let signature = webhook.sign(BODY).unwrap();
+        let seen = Mutex::new(HashSet::new());
+        let first = webhook
+            .validate_and_parse_dedup(BODY, signature.as_str(), "posts:publish", &seen)
+            .unwrap();
+        assert_eq!(first.unwrap().document_id(), "1");
+        let second = webhook
+            .validate_and_parse_dedup(BODY, signature.as_str(), "posts:publish", &seen)
+            .unwrap();
+        assert!(second.is_none());
+        let upper = webhook
+            .validate_and_parse_dedup(
+                BODY,
+                signature.to_ascii_uppercase().as_str(),
+                "posts:publish",
+                &seen,
+            )
+            .unwrap();
+        assert!(upper.is_none());
+        assert!(webhook
+            .validate_and_parse_dedup(BODY, "00", "posts:publish", &seen)
+            .is_err());
+    }
+
+    #[test]
+    fn empty_previous_secret_is_skipped() {
+        let webhook = Webhook {
+            webhook_secret: "new".to_string(),
+            previous_webhook_secrets: vec![String::new()],
+            ..Default::default()
+        };
+        let other = Webhook::with_secrets("other", &[]).sign(BODY).unwrap();
+        assert!(!webhook.check_signature(BODY, other.as_str()).unwrap());
+        assert!(Webhook::default()
+            .check_signature(BODY, other.as_str())
+            .is_err());
+    }
+
+    #[test]
+    fn empty_secret_is_reported_for_any_signature() {
+        for signature in ["00", "not hex", ""] {
+            match Webhook::default().check_signature(BODY, signature) {
+                Err(PatreonError::Message(message)) => {
+                    assert_eq!(message, "webhook secret is empty")
+                }
+                other => panic!("unexpected result {other:?} for {signature:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn long_secret_is_supported() {
+        // longer than the 64 byte MD5 block, HMAC hashes the key first
+        let secret = "s".repeat(100);
+        let webhook = Webhook::with_secrets(secret.as_str(), &[]);
+        let signature = webhook.sign(BODY).unwrap();
+        assert_eq!(signature.len(), 32);
+        assert!(webhook.check_signature(BODY, signature.as_str()).unwrap());
+        let truncated = Webhook::with_secrets(&secret[..64], &[]);
+        assert!(!truncated.check_signature(BODY, signature.as_str()).unwrap());
+    }
+
+    // shaped like a posts:publish delivery, ids and names replaced
+    const POST: &[u8] = br#"{
+        "data": {
+            "type": "post",
+            "id": "81234567",
+            "attributes": {
+                "app_id": null,
+                "app_status": null,
+                "content": "<p>New chapter is up</p>",
+                "embed_data": null,
+                "embed_url": null,
+                "is_paid": false,
+                "is_public": false,
+                "published_at": "2023-04-05T06:07:08.000+00:00",
+                "title": "Chapter 12",
+                "url": "/posts/chapter-12-81234567"
+            },
+            "relationships": {
+                "campaign": {
+                    "data": {"id": "1234567", "type": "campaign"},
+                    "links": {"related": "https://www.patreon.com/api/oauth2/v2/campaigns/1234567"}
+                },
+                "user": {
+                    "data": {"id": "7654321", "type": "user"},
+                    "links": {"related": "https://www.patreon.com/api/oauth2/v2/user/7654321"}
+                }
+            }
+        },
+        "included": [
+            {"type": "campaign", "id": "1234567", "attributes": {}},
+            {"type": "user", "id": "7654321", "attributes": {}}
+        ],
+        "links": {"self": "https://www.patreon.com/api/oauth2/v2/posts/81234567"}
+    }"#;
+
+    #[test]
+    fn post_triggers_parse_into_post_events() {
+        let webhook = Webhook::default();
+        for trigger in ["posts:publish", "posts:update", "posts:delete"] {
+            let event = webhook.parse_event(POST, trigger).unwrap();
+            let post = match (trigger, event) {
+                ("posts:publish", Event::PublishPost(post))
+                | ("posts:update", Event::UpdatePost(post))
+                | ("posts:delete", Event::DeletePost(post)) => post,
+                (trigger, _) => panic!("unexpected event for {trigger}"),
+            };
+            assert_eq!(post.id, "81234567");
+            assert_eq!(post.attributes.title.as_deref(), Some("Chapter 12"));
+            assert_eq!(post.attributes.url, "/posts/chapter-12-81234567");
+            assert!(post.attributes.published_at.is_some());
+        }
+    }
 }
@@ -0,0 +1,21 @@
+use patreon::prelude::*;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+// every public building block has to be reachable from the prelude alone
+#[test]
+fn prelude_names_public_types() {
+    let _: PatreonResult<()> = Err(PatreonError::NoCampaign);
+    let _ = PatreonApi::default().with_client_options(ClientOptions::default());
+    let _ = PatreonOAuth::default().with_retry_policy(RetryPolicy::default());
+    let _ = TokensResponse::default();
+    let _ = Webhook::default();
+    let seen: &dyn SeenStore = &Mutex::new(HashSet::new());
+    assert!(seen.insert("key".to_string()));
+    let _: Option<Event> = None;
+    let _: Vec<FieldChange> = MemberAttributes::default().diff(&MemberAttributes::default());
+    let _: Option<PledgeChange> = None;
+    let _: Option<(User, UserAttributes, Member, Campaign, CampaignAttributes)> = None;
+    let _: Option<(Pledge, PledgeAttributes, Post, PostAttributes)> = None;
+    let _: Option<(PatronStatus, LastChrgeStatus)> = None;
+}